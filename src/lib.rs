@@ -14,7 +14,7 @@
 //!
 //! for _ in 0..10 {
 //!     sleep(Duration::from_millis(100));
-//!     uuids.push(queue.enqueue(vec![], 30)?);
+//!     uuids.push(queue.enqueue(None, vec![], 30, None)?);
 //! }
 //!
 //! sleep(Duration::from_millis(10000));
@@ -43,8 +43,30 @@
 //! }
 //!
 //! let queue = Queue::new("redis://localhost/", "rjq");
-//! queue.work(process, None, Some(60), None, Some(30), Some(false), None)?;
+//! queue.work(process, None, Some(60), None, Some(30), Some(false), None, None, None, None,
+//!             None, None)?;
 //! ```
+//!
+//! # Backends
+//!
+//! `Queue` is generic over a `Backend` trait and defaults to `RedisBackend`,
+//! which preserves the key layout above. Swap in `EmbeddedBackend` (backed by
+//! `sled`) via `Queue::with_backend` for tests or small deployments that
+//! shouldn't need a running Redis.
+//!
+//! # Typed dispatch
+//!
+//! A single worker can process more than one kind of job by wrapping a
+//! `Queue` in a `Worker` and registering a handler per job type; jobs
+//! enqueued with `Queue::enqueue_typed` are dispatched to the matching
+//! handler instead of a single function passed to `Queue::work`.
+//!
+//! # Notifiers
+//!
+//! Pass a `Notifier` to `work` to get lifecycle callbacks (`job_started`,
+//! `job_finished`, `job_failed`, `job_lost`, `job_slow`) as jobs run, so
+//! operators can catch a long-running job before it's declared `LOST`
+//! instead of only learning about it afterwards.
 
 #![deny(missing_docs)]
 
@@ -52,26 +74,39 @@
 extern crate error_chain;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate lazy_static;
 extern crate serde;
 extern crate serde_json;
 extern crate redis;
 extern crate uuid;
+extern crate r2d2;
+extern crate r2d2_redis;
+extern crate sled;
+extern crate bincode;
 
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
 use std::thread;
+use std::panic;
 use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::thread::sleep;
 use std::marker::{Send, Sync};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
 use error_chain::ChainedError;
-use redis::{Commands, Client};
+use redis::{Commands, Script};
 use uuid::Uuid;
+use r2d2::{Pool, PooledConnection};
+use r2d2_redis::RedisConnectionManager;
 
 
 pub mod errors {
     #![allow(missing_docs)]
     use redis;
     use serde_json;
+    use bincode;
+    use sled;
     error_chain! {
         errors {
             JobFailed {
@@ -81,16 +116,50 @@ pub mod errors {
             JobQueued
             JobLost
             JobRunning
+            // job failed but is scheduled to be retried
+            JobRetrying(attempts: usize, next_run_at: u64) {
+                description("job failed and is scheduled to be retried")
+                display("job failed and will be retried (attempt {}, next run at {})", attempts, next_run_at)
+            }
+            // no job is stored under the requested id
+            JobNotFound(id: String) {
+                description("no job found for id")
+                display("no job found for id '{}'", id)
+            }
+            // couldn't obtain a pooled connection in time
+            PoolTimeout(message: String) {
+                description("timed out waiting for a pooled redis connection")
+                display("timed out waiting for a pooled redis connection: {}", message)
+            }
+            // the backend in use doesn't implement this feature
+            BackendUnsupported(feature: String) {
+                description("backend doesn't support this feature")
+                display("backend doesn't support: {}", feature)
+            }
+            // no handler registered for the job's type
+            JobServiceNotFound(job_type: String) {
+                description("no handler registered for job type")
+                display("no handler registered for job type '{}'", job_type)
+            }
+            // `work`'s concurrency would starve the backend's connection pool
+            ConcurrencyExceedsPoolSize(concurrency: usize, pool_size: u32) {
+                description("work concurrency exceeds the backend's pool size")
+                display("work concurrency ({}) must be less than the backend's pool size ({}); \
+                         each worker holds a connection for the duration of its blpop",
+                        concurrency, pool_size)
+            }
         }
 
         foreign_links {
             Redis(redis::RedisError);
             Serde(serde_json::Error);
+            Bincode(bincode::Error);
+            Sled(sled::Error);
         }
     }
 }
 
-pub use errors::{ErrorKind, Result};
+pub use errors::{ErrorKind, Result, ResultExt};
 
 /// Return type for the 'process' function; wraps
 /// an optional result String and an error type.
@@ -114,89 +183,585 @@ pub enum Status {
         /// Error backtrace
         backtrace: String,
     },
+    /// Job failed but will be retried after a backoff delay
+    RETRYING {
+        /// number of attempts made so far
+        attempts: usize,
+        /// unix timestamp (seconds) at which the job will next run
+        next_run_at: u64,
+    },
 }
 
+/// A single unit of work, as persisted by a `Backend`. Opaque to callers of
+/// `Queue`; backends serialize it however suits them (`RedisBackend` uses
+/// `serde_json`, `EmbeddedBackend` uses `bincode`).
 #[derive(Debug, Serialize, Deserialize)]
-struct Job {
+pub struct Job {
     id: String,
     status: Status,
     args: Vec<String>,
+    // number of times this job has been attempted so far
+    attempts: usize,
+    // maximum number of retries allowed before the job is marked `FAILED`,
+    // 0 disables retries entirely
+    max_retries: usize,
+    // handler name to dispatch to, set by `Queue::enqueue_typed`; `None` for
+    // jobs enqueued for a single-function `Queue::work` caller
+    job_type: Option<String>,
 }
 
 impl Job {
-    fn new(id: Option<String>, args: Vec<String>) -> Job {
+    fn new(id: Option<String>, args: Vec<String>, max_retries: usize, job_type: Option<String>) -> Job {
         Job {
             id: id.unwrap_or(Uuid::new_v4().to_string()),
             status: Status::QUEUED,
             args: args,
+            attempts: 0,
+            max_retries: max_retries,
+            job_type: job_type,
         }
     }
 }
 
-/// Queue
-pub struct Queue {
-    /// Redis url
-    url: String,
-    /// Queue name
+/// Default number of connections kept in the pool by `Queue::new`.
+const DEFAULT_POOL_SIZE: u32 = 10;
+
+/// Default base, in seconds, of the `base * 2^(attempts-1)` retry backoff.
+const DEFAULT_RETRY_BASE: usize = 1;
+
+/// Default ceiling, in seconds, on the retry backoff.
+const DEFAULT_RETRY_CEILING: usize = 3600;
+
+lazy_static! {
+    /// Atomically moves due ids from the scheduled sorted set (`KEYS[1]`)
+    /// onto the work queue (`KEYS[2]`): `ARGV[1]` is the current Unix
+    /// timestamp. Returns the ids that were moved.
+    static ref MOVE_SCHEDULED_SCRIPT: Script = Script::new(r#"
+        local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+        for _, id in ipairs(ids) do
+            redis.call('ZREM', KEYS[1], id)
+            redis.call('RPUSH', KEYS[2], id)
+        end
+        return ids
+    "#);
+}
+
+/// Storage backend used by `Queue` to persist jobs and move their ids
+/// around. `RedisBackend` is the default, preserving rjq's original key
+/// layout; implement this trait for an alternative backend (e.g. an
+/// in-memory store for tests).
+pub trait Backend: Clone + Send + Sync + 'static {
+    /// Push `id` onto the work queue, to be picked up by a waiting `blpop`.
+    fn push(&self, id: &str) -> Result<()>;
+
+    /// Block for up to `wait` seconds for an id to become available on the
+    /// work queue, returning `None` on timeout.
+    fn blpop(&self, wait: usize) -> Result<Option<String>>;
+
+    /// Persist `job` under `id` so `get_job` can read it back. `expire` is a
+    /// hint, in seconds, after which a backend that supports expiry may
+    /// discard the job.
+    fn set_job(&self, id: &str, job: &Job, expire: usize) -> Result<()>;
+
+    /// Fetch the job stored under `id`, if it still exists.
+    fn get_job(&self, id: &str) -> Result<Option<Job>>;
+
+    /// Delete every not-yet-popped job id from the work queue.
+    fn drop_queue(&self) -> Result<()>;
+
+    /// Schedule `id` to be moved back onto the work queue once `next_run_at`
+    /// (Unix seconds) has elapsed. Backs the delayed-enqueue and retry
+    /// machinery. Backends that can't support this should leave the
+    /// default, which reports the feature unsupported.
+    fn schedule(&self, id: &str, next_run_at: u64) -> Result<()> {
+        let _ = (id, next_run_at);
+        Err(ErrorKind::BackendUnsupported("scheduled jobs".to_string()).into())
+    }
+
+    /// Move every scheduled id whose `next_run_at` has elapsed onto the work
+    /// queue, returning the ids that were moved. See `schedule`.
+    fn poll_due(&self) -> Result<Vec<String>> {
+        Err(ErrorKind::BackendUnsupported("scheduled jobs".to_string()).into())
+    }
+
+    /// Number of connections a single blocking op (e.g. `blpop`) can tie up
+    /// concurrently, if the backend is pool-backed. `Queue::work` uses this
+    /// to make sure `concurrency` doesn't starve the pool; backends with no
+    /// such limit (e.g. `EmbeddedBackend`) should leave the default.
+    fn pool_size(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// Default storage backend, backed by Redis. Preserves rjq's original
+/// `{name}:ids` / `{name}:{id}` / `{name}:scheduled` key layout.
+#[derive(Clone)]
+pub struct RedisBackend {
     name: String,
+    pool: Pool<RedisConnectionManager>,
+}
+
+impl RedisBackend {
+    /// Connect to `url`, keeping up to `pool_size` pooled connections, for
+    /// the queue named `name`.
+    pub fn new(url: &str, name: &str, pool_size: u32) -> Result<RedisBackend> {
+        let manager = RedisConnectionManager::new(url)?;
+        let pool = Pool::builder().max_size(pool_size).build(manager)
+            .chain_err(|| "failed to build redis connection pool")?;
+
+        Ok(RedisBackend {
+            name: name.to_string(),
+            pool: pool,
+        })
+    }
+
+    /// Borrow a connection from the pool, translating a pool timeout into
+    /// `ErrorKind::PoolTimeout` so callers can tell it apart from a genuine
+    /// Redis error.
+    fn conn(&self) -> Result<PooledConnection<RedisConnectionManager>> {
+        self.pool.get().map_err(|err| ErrorKind::PoolTimeout(err.to_string()).into())
+    }
+
+    fn ids_key(&self) -> String {
+        format!("{}:ids", self.name)
+    }
+
+    fn job_key(&self, id: &str) -> String {
+        format!("{}:{}", self.name, id)
+    }
+
+    fn scheduled_key(&self) -> String {
+        format!("{}:scheduled", self.name)
+    }
+}
+
+impl Backend for RedisBackend {
+    fn push(&self, id: &str) -> Result<()> {
+        let conn = self.conn()?;
+        let _: () = conn.rpush(self.ids_key(), id)?;
+        Ok(())
+    }
+
+    fn blpop(&self, wait: usize) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let ids: Vec<String> = conn.blpop(self.ids_key(), wait)?;
+        Ok(ids.into_iter().nth(1))
+    }
+
+    fn set_job(&self, id: &str, job: &Job, expire: usize) -> Result<()> {
+        let conn = self.conn()?;
+        let _: () = conn.set_ex(self.job_key(id), serde_json::to_string(job)?, expire)?;
+        Ok(())
+    }
+
+    fn get_job(&self, id: &str) -> Result<Option<Job>> {
+        let conn = self.conn()?;
+        let json: Option<String> = conn.get(self.job_key(id))?;
+        match json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn drop_queue(&self) -> Result<()> {
+        let conn = self.conn()?;
+        let _: () = conn.del(self.ids_key())?;
+        Ok(())
+    }
+
+    fn schedule(&self, id: &str, next_run_at: u64) -> Result<()> {
+        let conn = self.conn()?;
+        let _: () = conn.zadd(self.scheduled_key(), id, next_run_at)?;
+        Ok(())
+    }
+
+    fn poll_due(&self) -> Result<Vec<String>> {
+        let mut conn = self.conn()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let ids: Vec<String> = MOVE_SCHEDULED_SCRIPT.key(self.scheduled_key())
+            .key(self.ids_key())
+            .arg(now)
+            .invoke(&mut *conn)?;
+
+        Ok(ids)
+    }
+
+    fn pool_size(&self) -> Option<u32> {
+        Some(self.pool.max_size())
+    }
+}
+
+/// In-memory/embedded storage backend, for tests and small deployments that
+/// don't need a running Redis. Job bodies are persisted to a `sled`
+/// database using `bincode` rather than `serde_json`; the work queue itself
+/// is an in-memory, per-process FIFO, since `sled` has no blocking-pop
+/// primitive to build `blpop` on. `expire` hints are not enforced.
+#[derive(Clone)]
+pub struct EmbeddedBackend {
+    db: sled::Db,
+    ids: Arc<(Mutex<VecDeque<String>>, Condvar)>,
+    scheduled: Arc<Mutex<BTreeMap<u64, Vec<String>>>>,
 }
 
-impl Queue {
-    /// Init new queue object
+impl EmbeddedBackend {
+    /// Open (or create) a `sled` database at `path` to back this queue.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<EmbeddedBackend> {
+        Ok(EmbeddedBackend {
+            db: sled::open(path)?,
+            ids: Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+            scheduled: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+}
+
+impl Backend for EmbeddedBackend {
+    fn push(&self, id: &str) -> Result<()> {
+        let &(ref queue, ref has_ids) = &*self.ids;
+        let mut queue = queue.lock().unwrap();
+        queue.push_back(id.to_string());
+        has_ids.notify_one();
+        Ok(())
+    }
+
+    fn blpop(&self, wait: usize) -> Result<Option<String>> {
+        let &(ref queue, ref has_ids) = &*self.ids;
+        let mut queue = queue.lock().unwrap();
+        if queue.is_empty() {
+            let (guard, _) = has_ids.wait_timeout(queue, Duration::from_secs(wait as u64)).unwrap();
+            queue = guard;
+        }
+        Ok(queue.pop_front())
+    }
+
+    fn set_job(&self, id: &str, job: &Job, _expire: usize) -> Result<()> {
+        let bytes = bincode::serialize(job)?;
+        self.db.insert(id.as_bytes(), bytes)?;
+        Ok(())
+    }
+
+    fn get_job(&self, id: &str) -> Result<Option<Job>> {
+        match self.db.get(id.as_bytes())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    fn drop_queue(&self) -> Result<()> {
+        let &(ref queue, _) = &*self.ids;
+        queue.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn schedule(&self, id: &str, next_run_at: u64) -> Result<()> {
+        self.scheduled.lock().unwrap().entry(next_run_at).or_insert_with(Vec::new).push(id.to_string());
+        Ok(())
+    }
+
+    fn poll_due(&self) -> Result<Vec<String>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let due: Vec<u64> = {
+            let scheduled = self.scheduled.lock().unwrap();
+            scheduled.range(..=now).map(|(at, _)| *at).collect()
+        };
+
+        let mut moved = Vec::new();
+        for at in due {
+            let ids = self.scheduled.lock().unwrap().remove(&at);
+            for id in ids.into_iter().flatten() {
+                self.push(&id)?;
+                moved.push(id);
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+/// Lifecycle hooks invoked by `Queue::work` as a job moves through its
+/// states, for operators who want visibility beyond polling `status`. Every
+/// method defaults to a no-op, so implementers only need to override the
+/// events they care about.
+pub trait Notifier: Send + Sync {
+    /// Called once a job has been popped off the queue and marked running.
+    fn job_started(&self, id: &str) {
+        let _ = id;
+    }
+
+    /// Called each time a run of a job ends as `FINISHED`, `FAILED` or
+    /// `LOST`, with how long it ran. A run that is then retried is reported
+    /// too, with the `FAILED` or `LOST` status it ended in.
+    fn job_finished(&self, id: &str, elapsed: Duration, status: &Status) {
+        let _ = (id, elapsed, status);
+    }
+
+    /// Called in addition to `job_finished` when the job ended `FAILED`.
+    fn job_failed(&self, id: &str, elapsed: Duration, message: &str) {
+        let _ = (id, elapsed, message);
+    }
+
+    /// Called in addition to `job_finished` when the job was declared
+    /// `LOST`.
+    fn job_lost(&self, id: &str, elapsed: Duration) {
+        let _ = (id, elapsed);
+    }
+
+    /// Called once, while a job is still running, when its elapsed time
+    /// first crosses the watchdog's slow threshold - a heads-up before the
+    /// job is declared `LOST` once `timeout` elapses. See the `work`
+    /// parameter `slow_threshold`.
+    fn job_slow(&self, id: &str, elapsed: Duration) {
+        let _ = (id, elapsed);
+    }
+}
+
+/// `Notifier` that does nothing; used by `Queue::work` when no notifier is
+/// supplied.
+#[derive(Clone, Copy, Default)]
+pub struct NoopNotifier;
+
+impl Notifier for NoopNotifier {}
+
+/// Queue of jobs, generic over the storage `Backend` used to persist and
+/// move them. Defaults to `RedisBackend`, preserving rjq's original
+/// behavior.
+pub struct Queue<B: Backend = RedisBackend> {
+    backend: B,
+}
+
+impl Queue<RedisBackend> {
+    /// Init new queue object, backed by a Redis connection pool of
+    /// `DEFAULT_POOL_SIZE` connections
     ///
     /// `url` - redis url to connect
     ///
     /// `name` - queue name
-    pub fn new(url: &str, name: &str) -> Queue {
-        Queue {
-            url: url.to_string(),
-            name: name.to_string(),
-        }
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection pool can't be built (e.g. `url` is
+    /// malformed). Use `with_pool` if you'd rather handle that as a
+    /// `Result`.
+    pub fn new(url: &str, name: &str) -> Queue<RedisBackend> {
+        Queue::with_pool(url, name, DEFAULT_POOL_SIZE)
+            .expect("failed to build redis connection pool")
+    }
+
+    /// Init new queue object with an explicitly sized Redis connection pool
+    ///
+    /// `url` - redis url to connect
+    ///
+    /// `name` - queue name
+    ///
+    /// `pool_size` - maximum number of connections kept in the pool
+    pub fn with_pool(url: &str, name: &str, pool_size: u32) -> Result<Queue<RedisBackend>> {
+        Ok(Queue { backend: RedisBackend::new(url, name, pool_size)? })
+    }
+}
+
+impl<B: Backend> Queue<B> {
+    /// Init a queue around an already-constructed backend, e.g. an
+    /// `EmbeddedBackend` for tests or small deployments that don't need a
+    /// running Redis.
+    pub fn with_backend(backend: B) -> Queue<B> {
+        Queue { backend: backend }
     }
 
     /// Delete enqueued jobs
     pub fn drop(&self) -> Result<()> {
-        let client = Client::open(self.url.as_str())?;
-        let conn = client.get_connection()?;
+        self.backend.drop_queue()
+    }
 
-        let _: () = conn.del(format!("{}:ids", self.name))?;
+    /// Enqueue new job
+    ///
+    /// `args` - job arguments
+    ///
+    /// `expire` - job expiration time in seconds, if hasn't started during this time it will be
+    /// removed
+    ///
+    /// `max_retries` - number of times to retry the job (with exponential backoff) if it fails
+    /// or is lost, 0 by default, which preserves the original single-shot behavior
+    ///
+    /// Returns unique job identifier
+    pub fn enqueue(&self,
+                    id: Option<&str>,
+                    args: Vec<String>,
+                    expire: usize,
+                    max_retries: Option<usize>)
+                    -> Result<String> {
+        let job = Job::new(id.map(|x| x.to_string()), args, max_retries.unwrap_or(0), None);
 
-        Ok(())
+        self.backend.set_job(&job.id, &job, expire)?;
+        self.backend.push(&job.id)?;
+
+        Ok(job.id)
     }
 
-    /// Enqueue new job
+    /// Enqueue a job tagged with `job_type`, for dispatch by a `Worker`
+    /// registry instead of a single handler function passed to `work`.
+    ///
+    /// `job_type` - name of the handler that should process this job, as
+    /// registered with `Worker::register`
+    ///
+    /// `args` - job arguments
+    ///
+    /// `expire` - job expiration time in seconds, if hasn't started during this time it will be
+    /// removed
+    ///
+    /// `max_retries` - number of times to retry the job (with exponential backoff) if it fails
+    /// or is lost, 0 by default, which preserves the original single-shot behavior
+    ///
+    /// Returns unique job identifier
+    pub fn enqueue_typed(&self,
+                          job_type: &str,
+                          id: Option<&str>,
+                          args: Vec<String>,
+                          expire: usize,
+                          max_retries: Option<usize>)
+                          -> Result<String> {
+        let job = Job::new(id.map(|x| x.to_string()), args, max_retries.unwrap_or(0),
+                            Some(job_type.to_string()));
+
+        self.backend.set_job(&job.id, &job, expire)?;
+        self.backend.push(&job.id)?;
+
+        Ok(job.id)
+    }
+
+    /// Enqueue a job that should only become eligible for `work` to pick up
+    /// at a future point in time.
+    ///
+    /// The job is persisted like `enqueue`, with its expiry extended by the
+    /// wait until `run_at`, but its id is handed to the backend's `schedule`
+    /// instead of `push`, so it only reaches the work queue once `run_at` has
+    /// elapsed.
     ///
     /// `args` - job arguments
     ///
     /// `expire` - job expiration time in seconds, if hasn't started during this time it will be
     /// removed
     ///
+    /// `run_at` - point in time at which the job becomes eligible to run
+    ///
+    /// `max_retries` - number of times to retry the job (with exponential backoff) if it fails
+    /// or is lost, 0 by default, which preserves the original single-shot behavior
+    ///
     /// Returns unique job identifier
-    pub fn enqueue(&self, id: Option<&str>, args: Vec<String>, expire: usize) -> Result<String> {
-        let client = Client::open(self.url.as_str())?;
-        let conn = client.get_connection()?;
+    pub fn enqueue_at(&self,
+                       id: Option<&str>,
+                       args: Vec<String>,
+                       expire: usize,
+                       run_at: SystemTime,
+                       max_retries: Option<usize>)
+                       -> Result<String> {
+        let job = Job::new(id.map(|x| x.to_string()), args, max_retries.unwrap_or(0), None);
 
-        let job = Job::new(id.map(|x| x.to_string()), args);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let score = run_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let delay = score.saturating_sub(now) as usize;
 
-        let _: () = conn.set_ex(format!("{}:{}", self.name, job.id),
-                                serde_json::to_string(&job)?,
-                                expire)?;
-        let _: () = conn.rpush(format!("{}:ids", self.name), &job.id)?;
+        // cover the wait until `run_at` as well as `expire` itself, or the job body
+        // would expire before `poll_due` ever moves it back onto the work queue
+        self.backend.set_job(&job.id, &job, delay + expire)?;
+        self.backend.schedule(&job.id, score)?;
 
         Ok(job.id)
     }
 
+    /// Enqueue a job that should only become eligible for `work` to pick up
+    /// after `delay` has elapsed. See `enqueue_at`.
+    ///
+    /// `args` - job arguments
+    ///
+    /// `expire` - job expiration time in seconds, if hasn't started during this time it will be
+    /// removed
+    ///
+    /// `delay` - how long to wait before the job becomes eligible to run
+    ///
+    /// `max_retries` - number of times to retry the job (with exponential backoff) if it fails
+    /// or is lost, 0 by default, which preserves the original single-shot behavior
+    ///
+    /// Returns unique job identifier
+    pub fn enqueue_in(&self,
+                       id: Option<&str>,
+                       args: Vec<String>,
+                       expire: usize,
+                       delay: Duration,
+                       max_retries: Option<usize>)
+                       -> Result<String> {
+        self.enqueue_at(id, args, expire, SystemTime::now() + delay, max_retries)
+    }
+
+    /// If `job` failed or was lost, bump its `attempts` and either schedule
+    /// it to run again after an exponential backoff (writing
+    /// `Status::RETRYING` and persisting the job itself) or, once
+    /// `max_retries` is exhausted, finalize it as `Status::FAILED`.
+    ///
+    /// Returns `true` if the job was rescheduled and already persisted, in
+    /// which case the caller doesn't need to write it again.
+    fn schedule_retry_if_needed(&self,
+                                 job: &mut Job,
+                                 expire: usize,
+                                 retry_base: usize,
+                                 retry_ceiling: usize)
+                                 -> Result<bool> {
+        let failure = match job.status {
+            Status::FAILED { ref message, ref backtrace } => (message.clone(), backtrace.clone()),
+            Status::LOST => ("job exceeded its timeout and was lost".to_string(), String::new()),
+            _ => return Ok(false),
+        };
+
+        job.attempts += 1;
+        if job.attempts > job.max_retries {
+            job.status = Status::FAILED { message: failure.0, backtrace: failure.1 };
+            return Ok(false);
+        }
+
+        // `1 << (attempts - 1)` would panic/wrap once attempts gets large; checked_shl
+        // and checked_mul turn that into "definitely past the ceiling" instead
+        let backoff = 1usize.checked_shl((job.attempts - 1) as u32)
+            .and_then(|factor| retry_base.checked_mul(factor))
+            .unwrap_or(usize::max_value())
+            .min(retry_ceiling);
+        let next_run_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() +
+            backoff as u64;
+
+        job.status = Status::RETRYING { attempts: job.attempts, next_run_at: next_run_at };
+
+        self.backend.set_job(&job.id, job, backoff + expire)?;
+        self.backend.schedule(&job.id, next_run_at)?;
+
+        Ok(true)
+    }
+
+    /// Poll for scheduled jobs whose `run_at` has elapsed, moving them onto
+    /// the work queue.
+    ///
+    /// `freq` - how often to poll, in seconds, 1 by default
+    ///
+    /// `infinite` - poll forever, true by default
+    pub fn poll_scheduled(&self, freq: Option<usize>, infinite: Option<bool>) -> Result<()> {
+        let freq = freq.unwrap_or(1);
+        let infinite = infinite.unwrap_or(true);
+
+        loop {
+            self.backend.poll_due()?;
+
+            if !infinite {
+                break;
+            }
+            sleep(Duration::from_secs(freq as u64));
+        }
+
+        Ok(())
+    }
+
     /// Get job status
     ///
     /// `id` - unique job identifier
     ///
     /// Returns job status
     pub fn status(&self, id: &str) -> Result<Status> {
-        let client = redis::Client::open(self.url.as_str())?;
-        let conn = client.get_connection()?;
-
-        let json: String = conn.get(format!("{}:{}", self.name, id))?;
-        let job: Job = serde_json::from_str(&json)?;
+        let job = self.backend.get_job(id)?.ok_or_else(|| ErrorKind::JobNotFound(id.to_string()))?;
 
         Ok(job.status)
     }
@@ -218,6 +783,22 @@ impl Queue {
     /// `fall` - panic if job was lost, true by default
     ///
     /// `infinite` - process jobs infinitely, true by default
+    ///
+    /// `retry_base` - base, in seconds, of the `retry_base * 2^(attempts-1)` backoff applied
+    /// between retries, 1 by default
+    ///
+    /// `retry_ceiling` - upper bound, in seconds, on the retry backoff, 3600 by default
+    ///
+    /// `concurrency` - number of worker threads to run the queue with, each independently
+    /// polling and processing jobs, 1 by default. Each worker holds a pooled connection for
+    /// the duration of its blpop, so for `RedisBackend` this must stay below the pool size
+    /// (see `with_pool`) or `work` returns `ErrorKind::ConcurrencyExceedsPoolSize`
+    ///
+    /// `notifier` - lifecycle hooks invoked as a job starts, runs long, and finishes; a
+    /// no-op `NoopNotifier` by default
+    ///
+    /// `slow_threshold` - fraction of `timeout` a still-running job's elapsed time must cross
+    /// before `notifier.job_slow` fires, 0.8 by default
     pub fn work<F: Fn(String, Vec<String>) -> JobResult + Send + Sync + 'static>
         (&self,
          fun: F,
@@ -226,34 +807,115 @@ impl Queue {
          freq: Option<usize>,
          expire: Option<usize>,
          fall: Option<bool>,
-         infinite: Option<bool>)
+         infinite: Option<bool>,
+         retry_base: Option<usize>,
+         retry_ceiling: Option<usize>,
+         concurrency: Option<usize>,
+         notifier: Option<Arc<Notifier>>,
+         slow_threshold: Option<f64>)
          -> Result<()> {
+        self.work_with_job_type(move |id, args, _job_type| fun(id, args), wait, timeout, freq,
+                                 expire, fall, infinite, retry_base, retry_ceiling, concurrency,
+                                 notifier, slow_threshold)
+    }
+
+    /// Like `work`, but `fun` also receives the job's `job_type` (as set by
+    /// `Queue::enqueue_typed`), so callers that already route on it - like
+    /// `Worker` - don't need a second `get_job` to recover it.
+    pub(crate) fn work_with_job_type<F>(&self,
+         fun: F,
+         wait: Option<usize>,
+         timeout: Option<usize>,
+         freq: Option<usize>,
+         expire: Option<usize>,
+         fall: Option<bool>,
+         infinite: Option<bool>,
+         retry_base: Option<usize>,
+         retry_ceiling: Option<usize>,
+         concurrency: Option<usize>,
+         notifier: Option<Arc<Notifier>>,
+         slow_threshold: Option<f64>)
+         -> Result<()>
+        where F: Fn(String, Vec<String>, Option<String>) -> JobResult + Send + Sync + 'static
+    {
         let wait = wait.unwrap_or(10);
         let timeout = timeout.unwrap_or(30);
         let freq = freq.unwrap_or(1);
         let expire = expire.unwrap_or(30);
         let fall = fall.unwrap_or(true);
         let infinite = infinite.unwrap_or(true);
+        let retry_base = retry_base.unwrap_or(DEFAULT_RETRY_BASE);
+        let retry_ceiling = retry_ceiling.unwrap_or(DEFAULT_RETRY_CEILING);
+        let concurrency = concurrency.unwrap_or(1);
+        let notifier = notifier.unwrap_or_else(|| Arc::new(NoopNotifier));
+        let slow_threshold = slow_threshold.unwrap_or(0.8);
 
-        let client = redis::Client::open(self.url.as_str())?;
-        let conn = client.get_connection()?;
+        // each worker holds a pooled connection for the duration of its blpop, so running
+        // as many workers as the pool has connections (or more) would starve the rest and
+        // surface as spurious PoolTimeout errors
+        if let Some(pool_size) = self.backend.pool_size() {
+            if concurrency as u32 >= pool_size {
+                return Err(ErrorKind::ConcurrencyExceedsPoolSize(concurrency, pool_size).into());
+            }
+        }
 
         let afun = Arc::new(fun);
-        let ids_key = format!("{}:ids", self.name);
+
+        let handles: Vec<thread::JoinHandle<Result<()>>> = (0..concurrency)
+            .map(|_| {
+                let worker = Queue { backend: self.backend.clone() };
+                let afun = afun.clone();
+                let notifier = notifier.clone();
+                thread::spawn(move || {
+                    worker.worker_loop(afun, wait, timeout, freq, expire, fall, infinite, retry_base,
+                                        retry_ceiling, notifier, slow_threshold)
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            match handle.join() {
+                Ok(result) => result?,
+                Err(panic) => panic::resume_unwind(panic),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Body of a single worker: blocks on `blpop`, then processes one job at
+    /// a time - spawning `fun` under a watchdog that marks the job `LOST` if
+    /// it overruns `timeout`. Run `concurrency` times in parallel by `work`
+    /// to process jobs concurrently.
+    fn worker_loop<F>(&self,
+         afun: Arc<F>,
+         wait: usize,
+         timeout: usize,
+         freq: usize,
+         expire: usize,
+         fall: bool,
+         infinite: bool,
+         retry_base: usize,
+         retry_ceiling: usize,
+         notifier: Arc<Notifier>,
+         slow_threshold: f64)
+         -> Result<()>
+        where F: Fn(String, Vec<String>, Option<String>) -> JobResult + Send + Sync + 'static
+    {
         loop {
-            let ids: Vec<String> = conn.blpop(&ids_key, wait)?;
-            if ids.len() < 2 {
-                if !infinite {
-                    break;
+            // move any due scheduled/retried ids onto the work queue before blocking on
+            // it, so callers using `work` alone (without a separate `poll_scheduled`
+            // loop) still see `enqueue_at`/`enqueue_in` jobs and retries run
+            if let Err(err) = self.backend.poll_due() {
+                match *err.kind() {
+                    ErrorKind::BackendUnsupported(_) => {},
+                    _ => return Err(err),
                 }
-                continue;
             }
 
-            let id = &ids[1].to_string();
-            let key = format!("{}:{}", self.name, id);
-            let json: String = match conn.get(&key) {
-                Ok(o) => o,
-                Err(_) => {
+            let id = match self.backend.blpop(wait)? {
+                Some(id) => id,
+                None => {
                     if !infinite {
                         break;
                     }
@@ -261,33 +923,61 @@ impl Queue {
                 }
             };
 
-            let mut job: Job = serde_json::from_str(&json)?;
+            let mut job = match self.backend.get_job(&id)? {
+                Some(job) => job,
+                None => {
+                    if !infinite {
+                        break;
+                    }
+                    continue;
+                }
+            };
 
             job.status = Status::RUNNING(None);
-            let _: () = conn.set_ex(&key, serde_json::to_string(&job)?, timeout + expire)?;
+            self.backend.set_job(&id, &job, timeout + expire)?;
+            notifier.job_started(&id);
 
             let (tx, rx) = channel();
             let cafun = afun.clone();
             let cid = id.clone();
             let cargs = job.args.clone();
+            let cjob_type = job.job_type.clone();
             thread::spawn(move || {
-                let r = match cafun(cid, cargs) {
-                    Ok(o) => Status::FINISHED(o),
-                    Err(err) => Status::FAILED {
-                        message: err.to_string(),
-                        backtrace: err.display_chain().to_string(),
+                let r = match cafun(cid, cargs, cjob_type) {
+                    Ok(o) => (Status::FINISHED(o), true),
+                    Err(err) => {
+                        // no handler will ever appear for this job_type in this process,
+                        // so retrying can only ever fail the same way again
+                        let retryable = match *err.kind() {
+                            ErrorKind::JobServiceNotFound(_) => false,
+                            _ => true,
+                        };
+                        (Status::FAILED {
+                            message: err.to_string(),
+                            backtrace: err.display_chain().to_string(),
+                        }, retryable)
                     },
                 };
                 tx.send(r).unwrap_or(())
             });
 
+            let started_at = Instant::now();
+            let slow_after = Duration::from_millis((timeout as f64 * slow_threshold * 1000.0) as u64);
+            let mut slow_notified = false;
+            let mut retryable = true;
+
             for _ in 0..(timeout * freq) {
-                let status = rx.try_recv().unwrap_or(Status::RUNNING(None));
+                let (status, status_retryable) = rx.try_recv().unwrap_or((Status::RUNNING(None), true));
                 job.status = status;
+                retryable = status_retryable;
                 match job.status {
                     Status::RUNNING(_) => {},
                     _ => break,
                 }
+                if !slow_notified && started_at.elapsed() >= slow_after {
+                    notifier.job_slow(&id, started_at.elapsed());
+                    slow_notified = true;
+                }
                 sleep(Duration::from_millis(1000 / freq as u64));
             }
             match job.status {
@@ -296,7 +986,21 @@ impl Queue {
                 },
                 _ => {}
             }
-            let _: () = conn.set_ex(&key, serde_json::to_string(&job)?, expire)?;
+
+            let elapsed = started_at.elapsed();
+            notifier.job_finished(&id, elapsed, &job.status);
+            match job.status {
+                Status::FAILED { ref message, .. } => notifier.job_failed(&id, elapsed, message),
+                Status::LOST => notifier.job_lost(&id, elapsed),
+                _ => {}
+            }
+
+            let retried = retryable && job.max_retries > 0 &&
+                self.schedule_retry_if_needed(&mut job, expire, retry_base, retry_ceiling)?;
+
+            if !retried {
+                self.backend.set_job(&id, &job, expire)?;
+            }
 
             if fall && job.status == Status::LOST {
                 panic!("LOST");
@@ -316,11 +1020,7 @@ impl Queue {
     ///
     /// Returns job result
     pub fn result(&self, id: &str) -> JobResult {
-        let client = redis::Client::open(self.url.as_str())?;
-        let conn = client.get_connection()?;
-
-        let json: String = conn.get(format!("{}:{}", self.name, id))?;
-        let job: Job = serde_json::from_str(&json)?;
+        let job = self.backend.get_job(id)?.ok_or_else(|| ErrorKind::JobNotFound(id.to_string()))?;
 
         match job.status {
             Status::FINISHED(result) => Ok(result),
@@ -329,6 +1029,166 @@ impl Queue {
                 Err(ErrorKind::JobFailed { message, backtrace }.into()),
             Status::LOST => Err(ErrorKind::JobLost.into()),
             Status::RUNNING(_) => Err(ErrorKind::JobRunning.into()),
+            Status::RETRYING { attempts, next_run_at } =>
+                Err(ErrorKind::JobRetrying(attempts, next_run_at).into()),
+        }
+    }
+}
+
+/// Registry of named job handlers built around a `Queue`, so a single
+/// worker process can handle several kinds of jobs instead of requiring one
+/// binary per job type. Jobs enqueued with `Queue::enqueue_typed` are
+/// dispatched to the handler registered under the matching `job_type`; jobs
+/// with no matching handler are marked `FAILED` with
+/// `ErrorKind::JobServiceNotFound`.
+pub struct Worker<B: Backend = RedisBackend> {
+    queue: Queue<B>,
+    handlers: HashMap<String, Arc<Fn(String, Vec<String>) -> JobResult + Send + Sync>>,
+}
+
+impl<B: Backend> Worker<B> {
+    /// Build a worker around `queue` with no handlers registered.
+    pub fn new(queue: Queue<B>) -> Worker<B> {
+        Worker { queue: queue, handlers: HashMap::new() }
+    }
+
+    /// Register `fun` to process jobs enqueued under `job_type` via
+    /// `Queue::enqueue_typed`. Returns `self` so registrations can be
+    /// chained.
+    pub fn register<F>(mut self, job_type: &str, fun: F) -> Worker<B>
+        where F: Fn(String, Vec<String>) -> JobResult + Send + Sync + 'static
+    {
+        self.handlers.insert(job_type.to_string(), Arc::new(fun));
+        self
+    }
+
+    /// Work on queue exactly like `Queue::work`, dispatching each job to the
+    /// handler registered for its `job_type` instead of a single function.
+    /// See `Queue::work` for the meaning of the other parameters.
+    pub fn work(&self,
+                wait: Option<usize>,
+                timeout: Option<usize>,
+                freq: Option<usize>,
+                expire: Option<usize>,
+                fall: Option<bool>,
+                infinite: Option<bool>,
+                retry_base: Option<usize>,
+                retry_ceiling: Option<usize>,
+                concurrency: Option<usize>,
+                notifier: Option<Arc<Notifier>>,
+                slow_threshold: Option<f64>)
+                -> Result<()> {
+        let handlers = self.handlers.clone();
+
+        self.queue.work_with_job_type(move |id, args, job_type| {
+            match job_type {
+                Some(ref job_type) => match handlers.get(job_type) {
+                    Some(handler) => handler(id, args),
+                    None => Err(ErrorKind::JobServiceNotFound(job_type.clone()).into()),
+                },
+                None => Err(ErrorKind::JobServiceNotFound("<untyped>".to_string()).into()),
+            }
+        }, wait, timeout, freq, expire, fall, infinite, retry_base, retry_ceiling, concurrency,
+           notifier, slow_threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // each test gets its own sled db so they don't trip over each other's state
+    fn embedded_queue() -> Queue<EmbeddedBackend> {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rjq-test-{}", Uuid::new_v4()));
+        Queue::with_backend(EmbeddedBackend::new(path).unwrap())
+    }
+
+    fn failing_job(_id: String, _args: Vec<String>) -> JobResult {
+        Err(ErrorKind::JobFailed { message: "boom".to_string(), backtrace: String::new() }.into())
+    }
+
+    #[test]
+    fn retries_until_max_retries_then_fails() {
+        let queue = embedded_queue();
+        let id = queue.enqueue(None, vec![], 30, Some(1)).unwrap();
+
+        // attempt 1: still within max_retries (1), gets rescheduled instead of failed
+        queue.work(failing_job, Some(1), Some(5), Some(50), None, Some(false), Some(false),
+                   Some(0), Some(0), None, None, None).unwrap();
+        match queue.status(&id).unwrap() {
+            Status::RETRYING { attempts, .. } => assert_eq!(attempts, 1),
+            other => panic!("expected RETRYING after first failure, got {:?}", other),
+        }
+        queue.poll_scheduled(Some(1), Some(false)).unwrap();
+
+        // attempt 2: exhausts max_retries, finalized as FAILED
+        queue.work(failing_job, Some(1), Some(5), Some(50), None, Some(false), Some(false),
+                   Some(0), Some(0), None, None, None).unwrap();
+        match queue.result(&id) {
+            Err(err) => match *err.kind() {
+                ErrorKind::JobFailed { .. } => {},
+                ref other => panic!("expected JobFailed, got {:?}", other),
+            },
+            Ok(_) => panic!("expected the job to have failed"),
+        }
+    }
+
+    #[test]
+    fn scheduled_job_becomes_eligible_via_poll_due() {
+        let queue = embedded_queue();
+        let id = queue.enqueue_in(None, vec![], 30, Duration::from_secs(0), None).unwrap();
+
+        // not on the work queue until poll_due moves it there
+        assert_eq!(queue.backend.blpop(0).unwrap(), None);
+
+        queue.poll_scheduled(Some(1), Some(false)).unwrap();
+
+        queue.work(|_id, _args| -> JobResult { Ok(None) },
+                   Some(1), Some(5), Some(50), None, Some(false), Some(false), None, None, None,
+                   None, None).unwrap();
+
+        assert_eq!(queue.result(&id).unwrap(), None);
+    }
+
+    #[test]
+    fn backoff_is_capped_at_retry_ceiling() {
+        let queue = embedded_queue();
+        let mut job = Job::new(None, vec![], 100, None);
+        job.status = Status::FAILED { message: "boom".to_string(), backtrace: String::new() };
+        job.attempts = 10;
+
+        let retried = queue.schedule_retry_if_needed(&mut job, 30, 1, 5).unwrap();
+        assert!(retried);
+
+        match job.status {
+            Status::RETRYING { next_run_at, .. } => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                assert!(next_run_at <= now + 5);
+            },
+            ref other => panic!("expected RETRYING, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn typed_dispatch_and_unknown_job_type() {
+        let queue = embedded_queue();
+        let worker = Worker::new(Queue::with_backend(queue.backend.clone()))
+            .register("known", |_id, _args| -> JobResult { Ok(Some("done".to_string())) });
+
+        let known_id = queue.enqueue_typed("known", None, vec![], 30, None).unwrap();
+        worker.work(Some(1), Some(5), Some(50), None, Some(false), Some(false), None, None, None,
+                    None, None).unwrap();
+        assert_eq!(queue.result(&known_id).unwrap(), Some("done".to_string()));
+
+        // max_retries is high enough that a retryable failure would be rescheduled instead
+        // of finalized; JobServiceNotFound must be terminal regardless
+        let unknown_id = queue.enqueue_typed("missing", None, vec![], 30, Some(3)).unwrap();
+        worker.work(Some(1), Some(5), Some(50), None, Some(false), Some(false), None, None, None,
+                    None, None).unwrap();
+        match queue.status(&unknown_id).unwrap() {
+            Status::FAILED { ref message, .. } => assert!(message.contains("missing")),
+            other => panic!("expected a terminal FAILED status, got {:?}", other),
         }
     }
 }